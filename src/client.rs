@@ -0,0 +1,154 @@
+//! A minimal SOCKS5 client for making outbound connections through a SOCKS5
+//! proxy, mirroring the handshake the server performs in
+//! [`crate::server::Upstream::Socks5`].
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::TcpStream;
+
+use crate::common::{
+    read_exact, read_socks5_reply, write_all, ADDRESS_TYPE_DOMAIN_NAME, ADDRESS_TYPE_IPV4,
+    ADDRESS_TYPE_IPV6, AUTH_SUCCESS, AUTH_VERSION, NO_ACCEPTABLE_METHODS,
+    NO_AUTHENTICATION_REQUIRED, PROTOCOL_VERSION, PROXY_CMD_CONNECT, REPLY_SUCCEEDED, RESERVED,
+    USERNAME_PASSWORD_AUTHENTICATION,
+};
+
+/// RFC 1929 username/password credentials to present during the handshake.
+pub struct Auth {
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+/// The destination to request via the proxy's CONNECT command.
+pub enum Target {
+    Ip(IpAddr, u16),
+    Domain(String, u16),
+}
+
+/// A `TcpStream` connected through a SOCKS5 proxy to a [`Target`]. Once
+/// established, reads and writes on the stream returned by
+/// [`Socks5Stream::into_inner`] carry the proxied application data directly.
+pub struct Socks5Stream {
+    stream: TcpStream,
+    bound_addr: SocketAddr,
+}
+
+impl Socks5Stream {
+    /// The address the proxy bound for this connection (`BND.ADDR`/`BND.PORT`).
+    pub fn bound_addr(&self) -> SocketAddr {
+        self.bound_addr
+    }
+
+    /// Unwraps the underlying `TcpStream` to the proxy.
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+/// Performs the SOCKS5 method-negotiation handshake, optional RFC 1929
+/// username/password authentication, and a CONNECT request over `stream`,
+/// which must already be connected to the proxy. Domain targets are
+/// forwarded unresolved so the proxy performs its own DNS resolution.
+pub async fn connect(
+    stream: TcpStream,
+    target: Target,
+    auth: Option<Auth>,
+) -> io::Result<Socks5Stream> {
+    let methods: Vec<u8> = if auth.is_some() {
+        vec![NO_AUTHENTICATION_REQUIRED, USERNAME_PASSWORD_AUTHENTICATION]
+    } else {
+        vec![NO_AUTHENTICATION_REQUIRED]
+    };
+
+    let mut hello = vec![PROTOCOL_VERSION, methods.len() as u8];
+    hello.extend_from_slice(&methods);
+    write_all(&stream, &hello).await?;
+
+    let mut method_reply = [0u8; 2];
+    read_exact(&stream, &mut method_reply).await?;
+
+    if method_reply[0] != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected SOCKS5 version in method reply",
+        ));
+    }
+
+    match method_reply[1] {
+        NO_AUTHENTICATION_REQUIRED => {}
+        USERNAME_PASSWORD_AUTHENTICATION => {
+            let auth = auth.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "proxy requires username/password authentication",
+                )
+            })?;
+
+            if auth.username.len() > 255 || auth.password.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "RFC 1929 username/password must each be at most 255 bytes",
+                ));
+            }
+
+            let mut request = vec![AUTH_VERSION, auth.username.len() as u8];
+            request.extend_from_slice(&auth.username);
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(&auth.password);
+            write_all(&stream, &request).await?;
+
+            let mut auth_reply = [0u8; 2];
+            read_exact(&stream, &mut auth_reply).await?;
+            if auth_reply[1] != AUTH_SUCCESS {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS5 username/password authentication failed",
+                ));
+            }
+        }
+        NO_ACCEPTABLE_METHODS => {
+            return Err(io::Error::other(
+                "proxy rejected all offered authentication methods",
+            ));
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported SOCKS5 authentication method",
+            ));
+        }
+    }
+
+    let mut request = vec![PROTOCOL_VERSION, PROXY_CMD_CONNECT, RESERVED];
+    let port = match &target {
+        Target::Ip(IpAddr::V4(ipv4), port) => {
+            request.push(ADDRESS_TYPE_IPV4);
+            request.extend_from_slice(&ipv4.octets());
+            *port
+        }
+        Target::Ip(IpAddr::V6(ipv6), port) => {
+            request.push(ADDRESS_TYPE_IPV6);
+            request.extend_from_slice(&ipv6.octets());
+            *port
+        }
+        Target::Domain(domain, port) => {
+            request.push(ADDRESS_TYPE_DOMAIN_NAME);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    write_all(&stream, &request).await?;
+
+    let (reply, bound_addr) = read_socks5_reply(&stream).await?;
+    if reply != REPLY_SUCCEEDED {
+        return Err(io::Error::other(format!(
+            "SOCKS5 CONNECT failed with reply code {:#04x}",
+            reply
+        )));
+    }
+
+    Ok(Socks5Stream { stream, bound_addr })
+}
@@ -0,0 +1,124 @@
+//! SOCKS5 wire-protocol constants and low-level I/O helpers shared between
+//! [`crate::server`] and [`crate::client`].
+
+use std::io;
+use tokio::net::TcpStream;
+
+pub(crate) const PROTOCOL_VERSION: u8 = 0x05;
+
+pub(crate) const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+pub(crate) const USERNAME_PASSWORD_AUTHENTICATION: u8 = 0x02;
+pub(crate) const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+pub(crate) const AUTH_VERSION: u8 = 0x01;
+pub(crate) const AUTH_SUCCESS: u8 = 0x00;
+pub(crate) const AUTH_FAILURE: u8 = 0x01;
+
+pub(crate) const PROXY_CMD_CONNECT: u8 = 0x01;
+// const PROXY_CMD_BIND: u8 = 0x02;
+pub(crate) const PROXY_CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub(crate) const ADDRESS_TYPE_IPV4: u8 = 0x01;
+pub(crate) const ADDRESS_TYPE_DOMAIN_NAME: u8 = 0x03;
+pub(crate) const ADDRESS_TYPE_IPV6: u8 = 0x04;
+
+pub(crate) const REPLY_SUCCEEDED: u8 = 0x00;
+pub(crate) const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const REPLY_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+pub(crate) const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub(crate) const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub(crate) const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub(crate) const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub(crate) const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub(crate) const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+// const REPLY_UNASSIGNED: u8 = 0x09;
+
+pub(crate) const RESERVED: u8 = 0x00;
+
+/// Reads a SOCKS5 reply (`VER REP RSV ATYP BND.ADDR BND.PORT`) off a
+/// handshake stream, consuming the whole message, and returns the `REP`
+/// byte together with the bound address. Domain-name `BND.ADDR`s are
+/// consumed but reported back as `0.0.0.0`, since servers in practice only
+/// ever bind IPv4/IPv6 addresses.
+pub(crate) async fn read_socks5_reply(
+    stream: &TcpStream,
+) -> std::io::Result<(u8, std::net::SocketAddr)> {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    let mut header = [0u8; 4];
+    read_exact(stream, &mut header).await?;
+
+    let ip = match header[3] {
+        ADDRESS_TYPE_IPV4 => {
+            let mut octets = [0u8; 4];
+            read_exact(stream, &mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ADDRESS_TYPE_IPV6 => {
+            let mut octets = [0u8; 16];
+            read_exact(stream, &mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        ADDRESS_TYPE_DOMAIN_NAME => {
+            let mut len_buf = [0u8; 1];
+            read_exact(stream, &mut len_buf).await?;
+            let mut domain = vec![0u8; len_buf[0] as usize];
+            read_exact(stream, &mut domain).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown address type in SOCKS5 reply",
+            ))
+        }
+    };
+
+    let mut port_buf = [0u8; 2];
+    read_exact(stream, &mut port_buf).await?;
+    let port = u16::from_be_bytes(port_buf);
+
+    Ok((header[1], SocketAddr::new(ip, port)))
+}
+
+pub(crate) async fn read_exact(stream: &TcpStream, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        stream.readable().await?;
+
+        match stream.try_read(&mut buf[offset..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                offset += n;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                continue;
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn write_all(stream: &TcpStream, buf: &[u8]) -> std::io::Result<()> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        stream.writable().await?;
+
+        match stream.try_write(&buf[offset..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                offset += n;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                continue;
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod server;
+
+mod common;
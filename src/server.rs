@@ -1,15 +1,67 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 
 mod connection;
 
+/// Where `SOCKS5ClientConnection` should dial outbound connections.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    /// Connect to the requested destination directly.
+    Direct,
+    /// Route CONNECT requests through another SOCKS5 proxy (e.g. a local
+    /// Tor instance at `127.0.0.1:9050`), optionally authenticating with
+    /// RFC 1929 username/password credentials.
+    Socks5 {
+        addr: SocketAddr,
+        auth: Option<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+/// Verifies RFC 1929 username/password credentials presented during the
+/// SOCKS5 handshake.
+pub trait Authenticator: Send + Sync {
+    fn verify(&self, user: &[u8], pass: &[u8]) -> bool;
+}
+
+/// An `Authenticator` backed by a fixed table of username/password pairs.
+pub struct StaticAuthenticator {
+    users: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(users: HashMap<Vec<u8>, Vec<u8>>) -> Self {
+        StaticAuthenticator { users }
+    }
+}
+
+impl Authenticator for StaticAuthenticator {
+    fn verify(&self, user: &[u8], pass: &[u8]) -> bool {
+        self.users.get(user).map(|p| p.as_slice()) == Some(pass)
+    }
+}
+
 pub struct SOCKS5Server {
     address: String,
     port: u16,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    upstream: Upstream,
 }
 
 impl SOCKS5Server {
-    pub fn new(address: String, port: u16) -> Self {
-        SOCKS5Server { address, port }
+    pub fn new(
+        address: String,
+        port: u16,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        upstream: Upstream,
+    ) -> Self {
+        SOCKS5Server {
+            address,
+            port,
+            authenticator,
+            upstream,
+        }
     }
 
     pub async fn run(&self) {
@@ -18,10 +70,43 @@ impl SOCKS5Server {
 
         loop {
             let (socket, _) = listener.accept().await.unwrap();
-            let mut connection = connection::SOCKS5ClientConnection::new(socket);
+            let authenticator = self.authenticator.clone();
+            let upstream = self.upstream.clone();
+            let mut connection =
+                connection::SOCKS5ClientConnection::new(socket, authenticator, upstream);
             tokio::spawn(async move {
                 connection.process().await;
             });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_authenticator_accepts_matching_credentials() {
+        let mut users = HashMap::new();
+        users.insert(b"alice".to_vec(), b"hunter2".to_vec());
+        let auth = StaticAuthenticator::new(users);
+
+        assert!(auth.verify(b"alice", b"hunter2"));
+    }
+
+    #[test]
+    fn static_authenticator_rejects_wrong_password() {
+        let mut users = HashMap::new();
+        users.insert(b"alice".to_vec(), b"hunter2".to_vec());
+        let auth = StaticAuthenticator::new(users);
+
+        assert!(!auth.verify(b"alice", b"wrong"));
+    }
+
+    #[test]
+    fn static_authenticator_rejects_unknown_user() {
+        let auth = StaticAuthenticator::new(HashMap::new());
+
+        assert!(!auth.verify(b"alice", b"hunter2"));
+    }
+}
@@ -4,33 +4,34 @@ use std::io;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
-use std::net::ToSocketAddrs;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpStream;
-
-const PROTOCOL_VERSION: u8 = 0x05;
-
-const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
-
-const PROXY_CMD_CONNECT: u8 = 0x01;
-// const PROXY_CMD_BIND: u8 = 0x02;
-// const PROXY_CMD_UDP_ASSOCIATE: u8 = 0x03;
-
-const ADDRESS_TYPE_IPV4: u8 = 0x01;
-const ADDRESS_TYPE_DOMAIN_NAME: u8 = 0x03;
-const ADDRESS_TYPE_IPV6: u8 = 0x04;
-
-const REPLY_SUCCEEDED: u8 = 0x00;
-// const REPLY_GENERAL_FAILURE: u8 = 0x01;
-// const REPLY_CONNECTION_NOT_ALLOWED: u8 = 0x02;
-// const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
-// const REPLY_HOST_UNREACHABLE: u8 = 0x04;
-const REPLY_CONNECTION_REFUSED: u8 = 0x05;
-// const REPLY_TTL_EXPIRED: u8 = 0x06;
-// const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
-// const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
-// const REPLY_UNASSIGNED: u8 = 0x09;
-
-const RESERVED: u8 = 0x00;
+use tokio::net::UdpSocket;
+
+use super::Authenticator;
+use super::Upstream;
+
+use crate::common::{
+    read_exact, read_socks5_reply, write_all, ADDRESS_TYPE_DOMAIN_NAME, ADDRESS_TYPE_IPV4,
+    ADDRESS_TYPE_IPV6, AUTH_FAILURE, AUTH_SUCCESS, AUTH_VERSION, NO_ACCEPTABLE_METHODS,
+    NO_AUTHENTICATION_REQUIRED, PROTOCOL_VERSION, PROXY_CMD_CONNECT, PROXY_CMD_UDP_ASSOCIATE,
+    REPLY_ADDRESS_TYPE_NOT_SUPPORTED, REPLY_COMMAND_NOT_SUPPORTED, REPLY_CONNECTION_NOT_ALLOWED,
+    REPLY_CONNECTION_REFUSED, REPLY_GENERAL_FAILURE, REPLY_HOST_UNREACHABLE,
+    REPLY_NETWORK_UNREACHABLE, REPLY_SUCCEEDED, REPLY_TTL_EXPIRED, RESERVED,
+    USERNAME_PASSWORD_AUTHENTICATION,
+};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+// Matches the longest field SOCKS5 itself allows (a length-prefixed domain
+// name), so a SOCKS4/4a request can't make us buffer an unbounded USERID or
+// hostname.
+const SOCKS4_MAX_FIELD_LEN: usize = 255;
+
+const UDP_FRAGMENT_STANDALONE: u8 = 0x00;
 
 const BUFFER_SIZE: usize = 4096;
 
@@ -38,6 +39,7 @@ const BUFFER_SIZE: usize = 4096;
 enum SOCKS5ConnectionErr {
     InvalidVersion,
     UnsupportedAuthMethod,
+    AuthenticationFailed,
     UnsupportedCommand,
     InvalidAddressType,
     ConnectionFailed,
@@ -46,13 +48,28 @@ enum SOCKS5ConnectionErr {
 #[derive(Debug)]
 enum SOCKS5Command {
     Connect(Address),
+    UdpAssociate(Address),
     // Bind,
-    // UdpAssociate,
+}
+
+#[derive(Debug)]
+enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(ip) => write!(f, "{}", ip),
+            Host::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Address {
-    host: IpAddr,
+    host: Host,
     port: u16,
 }
 
@@ -66,20 +83,45 @@ impl fmt::Display for SOCKS5ConnectionErr {
             SOCKS5ConnectionErr::UnsupportedAuthMethod => {
                 write!(f, "Unsupported SOCKS5 authentication method")
             }
+            SOCKS5ConnectionErr::AuthenticationFailed => {
+                write!(f, "SOCKS5 username/password authentication failed")
+            }
         }
     }
 }
 
 pub struct SOCKS5ClientConnection {
     stream: TcpStream,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    upstream: Upstream,
 }
 
 impl SOCKS5ClientConnection {
-    pub fn new(stream: TcpStream) -> Self {
-        SOCKS5ClientConnection { stream }
+    pub fn new(
+        stream: TcpStream,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        upstream: Upstream,
+    ) -> Self {
+        SOCKS5ClientConnection {
+            stream,
+            authenticator,
+            upstream,
+        }
     }
 
     pub async fn process(&mut self) {
+        let mut first_byte = [0u8; 1];
+        match self.stream.peek(&mut first_byte).await {
+            Ok(1) if first_byte[0] == SOCKS4_VERSION => {
+                if let Err(e) = self.process_socks4().await {
+                    debug!("SOCKS4 request failed: {}", e);
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(_) => return,
+        }
+
         if let Err(e) = self.handle_shake().await {
             debug!("SOCKS5 handshake failed: {}", e);
             return;
@@ -92,8 +134,20 @@ impl SOCKS5ClientConnection {
                     debug!("SOCKS5 connect failed: {}", e);
                 }
             }
+            Ok(SOCKS5Command::UdpAssociate(addr)) => {
+                debug!(
+                    "SOCKS5 request: UDP associate from {}:{}",
+                    addr.host, addr.port
+                );
+                if let Err(e) = self.process_udp_associate().await {
+                    debug!("SOCKS5 UDP associate failed: {}", e);
+                }
+            }
             Err(e) => {
                 debug!("SOCKS5 command failed: {}", e);
+                if let Some(reply) = command_err_reply(&e) {
+                    self.send_failure_reply(reply).await;
+                }
             }
         };
     }
@@ -109,29 +163,79 @@ impl SOCKS5ClientConnection {
         let num_methods = buf[1] as usize;
 
         let mut methods = vec![0; num_methods];
-        for _ in 0..num_methods {
-            let mut buf = [0u8; 1];
-            read_exact(&self.stream, &mut buf).await.unwrap();
-            methods.push(buf[0]);
-        }
+        read_exact(&self.stream, &mut methods).await.unwrap();
 
-        if !methods.contains(&0x00) {
+        let method = if self.authenticator.is_some()
+            && methods.contains(&USERNAME_PASSWORD_AUTHENTICATION)
+        {
+            USERNAME_PASSWORD_AUTHENTICATION
+        } else if self.authenticator.is_none() && methods.contains(&NO_AUTHENTICATION_REQUIRED) {
+            NO_AUTHENTICATION_REQUIRED
+        } else {
+            if let Err(_) =
+                write_all(&self.stream, &[PROTOCOL_VERSION, NO_ACCEPTABLE_METHODS]).await
+            {
+                return Err(SOCKS5ConnectionErr::ConnectionFailed);
+            }
             return Err(SOCKS5ConnectionErr::UnsupportedAuthMethod);
-        }
+        };
 
         // send the SOCKS5 handshake response
-        if let Err(_) = write_all(
-            &self.stream,
-            &[PROTOCOL_VERSION, NO_AUTHENTICATION_REQUIRED],
-        )
-        .await
-        {
+        if let Err(_) = write_all(&self.stream, &[PROTOCOL_VERSION, method]).await {
             return Err(SOCKS5ConnectionErr::ConnectionFailed);
         }
 
+        if method == USERNAME_PASSWORD_AUTHENTICATION {
+            self.authenticate().await?;
+        }
+
         Ok(())
     }
 
+    async fn authenticate(&mut self) -> Result<(), SOCKS5ConnectionErr> {
+        let mut buf = [0u8; 2];
+        if let Err(_) = read_exact(&self.stream, &mut buf).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        if buf[0] != AUTH_VERSION {
+            return Err(SOCKS5ConnectionErr::InvalidVersion);
+        }
+
+        let ulen = buf[1] as usize;
+        let mut username = vec![0; ulen];
+        if let Err(_) = read_exact(&self.stream, &mut username).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        let mut buf = [0u8; 1];
+        if let Err(_) = read_exact(&self.stream, &mut buf).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        let plen = buf[0] as usize;
+        let mut password = vec![0; plen];
+        if let Err(_) = read_exact(&self.stream, &mut password).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        let authenticated = self
+            .authenticator
+            .as_ref()
+            .is_some_and(|auth| auth.verify(&username, &password));
+
+        let status = if authenticated { AUTH_SUCCESS } else { AUTH_FAILURE };
+        if let Err(_) = write_all(&self.stream, &[AUTH_VERSION, status]).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        if authenticated {
+            Ok(())
+        } else {
+            Err(SOCKS5ConnectionErr::AuthenticationFailed)
+        }
+    }
+
     async fn parse_command(&mut self) -> Result<SOCKS5Command, SOCKS5ConnectionErr> {
         // read the SOCKS5 request
         let mut buf = [0; 4];
@@ -144,7 +248,8 @@ impl SOCKS5ClientConnection {
             return Err(SOCKS5ConnectionErr::InvalidVersion);
         }
 
-        if buf[1] != PROXY_CMD_CONNECT {
+        let command = buf[1];
+        if command != PROXY_CMD_CONNECT && command != PROXY_CMD_UDP_ASSOCIATE {
             return Err(SOCKS5ConnectionErr::UnsupportedCommand);
         }
 
@@ -158,7 +263,7 @@ impl SOCKS5ClientConnection {
                     return Err(SOCKS5ConnectionErr::ConnectionFailed);
                 }
                 address_info.extend_from_slice(&buf);
-                IpAddr::V4(Ipv4Addr::from(buf))
+                Host::Ip(IpAddr::V4(Ipv4Addr::from(buf)))
             }
             ADDRESS_TYPE_IPV6 => {
                 let mut buf = [0; 16];
@@ -166,7 +271,7 @@ impl SOCKS5ClientConnection {
                     return Err(SOCKS5ConnectionErr::ConnectionFailed);
                 }
                 address_info.extend_from_slice(&buf);
-                IpAddr::V6(Ipv6Addr::from(buf))
+                Host::Ip(IpAddr::V6(Ipv6Addr::from(buf)))
             }
             ADDRESS_TYPE_DOMAIN_NAME => {
                 let mut buf = [0; 1];
@@ -182,9 +287,7 @@ impl SOCKS5ClientConnection {
                     return Err(SOCKS5ConnectionErr::ConnectionFailed);
                 }
                 address_info.extend_from_slice(&buf);
-                let domain = String::from_utf8_lossy(&buf);
-                let mut addrs = (domain.as_ref(), 0).to_socket_addrs().unwrap();
-                addrs.next().unwrap().ip()
+                Host::Domain(String::from_utf8_lossy(&buf).into_owned())
             }
             _ => {
                 return Err(SOCKS5ConnectionErr::InvalidAddressType);
@@ -203,26 +306,18 @@ impl SOCKS5ClientConnection {
             port: port,
         };
 
-        Ok(SOCKS5Command::Connect(addr))
+        if command == PROXY_CMD_UDP_ASSOCIATE {
+            Ok(SOCKS5Command::UdpAssociate(addr))
+        } else {
+            Ok(SOCKS5Command::Connect(addr))
+        }
     }
 
     async fn process_connect(&mut self, addr: Address) -> Result<(), SOCKS5ConnectionErr> {
-        let dest_stream = match TcpStream::connect((addr.host, addr.port)).await {
+        let dest_stream = match self.connect_to_target(&addr).await {
             Ok(stream) => stream,
-            Err(_) => {
-                let data = vec![
-                    PROTOCOL_VERSION,
-                    REPLY_CONNECTION_REFUSED,
-                    RESERVED,
-                    ADDRESS_TYPE_IPV4,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                ];
-                write_all(&self.stream, &data).await.unwrap();
+            Err(reply) => {
+                self.send_failure_reply(reply).await;
                 return Err(SOCKS5ConnectionErr::ConnectionFailed);
             }
         };
@@ -247,6 +342,105 @@ impl SOCKS5ClientConnection {
 
         write_all(&self.stream, &mut data).await.unwrap();
 
+        self.relay(dest_stream).await;
+
+        Ok(())
+    }
+
+    async fn process_socks4(&mut self) -> Result<(), SOCKS5ConnectionErr> {
+        // VN CD DSTPORT DSTIP
+        let mut buf = [0u8; 8];
+        if let Err(_) = read_exact(&self.stream, &mut buf).await {
+            return Err(SOCKS5ConnectionErr::ConnectionFailed);
+        }
+
+        let command = buf[1];
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+        // consume the NUL-terminated USERID, bounded so a client can't make
+        // us buffer an unterminated stream forever
+        match read_nul_terminated_field(&self.stream, SOCKS4_MAX_FIELD_LEN).await {
+            Ok(_) => {}
+            Err(FieldReadErr::TooLong) => {
+                self.send_socks4_reply(SOCKS4_REJECTED).await;
+                return Err(SOCKS5ConnectionErr::ConnectionFailed);
+            }
+            Err(FieldReadErr::Io) => return Err(SOCKS5ConnectionErr::ConnectionFailed),
+        }
+
+        // SOCKS4a: an IP of the form 0.0.0.x (x != 0) means the real
+        // destination follows as a NUL-terminated hostname.
+        let octets = ip.octets();
+        let is_socks4a = octets[0] == 0 && octets[1] == 0 && octets[2] == 0 && octets[3] != 0;
+
+        let host = if is_socks4a {
+            let domain = match read_nul_terminated_field(&self.stream, SOCKS4_MAX_FIELD_LEN).await
+            {
+                Ok(domain) => domain,
+                Err(FieldReadErr::TooLong) => {
+                    self.send_socks4_reply(SOCKS4_REJECTED).await;
+                    return Err(SOCKS5ConnectionErr::ConnectionFailed);
+                }
+                Err(FieldReadErr::Io) => return Err(SOCKS5ConnectionErr::ConnectionFailed),
+            };
+            Host::Domain(String::from_utf8_lossy(&domain).into_owned())
+        } else {
+            Host::Ip(IpAddr::V4(ip))
+        };
+
+        if command != SOCKS4_CMD_CONNECT {
+            self.send_socks4_reply(SOCKS4_REJECTED).await;
+            return Err(SOCKS5ConnectionErr::UnsupportedCommand);
+        }
+
+        let addr = Address { host, port };
+        debug!("SOCKS4 request: connect to {}:{}", addr.host, addr.port);
+
+        let dest_stream = match self.connect_to_target(&addr).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                self.send_socks4_reply(SOCKS4_REJECTED).await;
+                return Err(SOCKS5ConnectionErr::ConnectionFailed);
+            }
+        };
+
+        self.send_socks4_reply(SOCKS4_GRANTED).await;
+        self.relay(dest_stream).await;
+
+        Ok(())
+    }
+
+    /// Resolves and connects to `addr`, either directly or through the
+    /// configured upstream proxy. Returns the SOCKS5 reply code to report
+    /// back to the client on failure.
+    async fn connect_to_target(&self, addr: &Address) -> Result<TcpStream, u8> {
+        match self.upstream.clone() {
+            Upstream::Direct => {
+                let target = match &addr.host {
+                    Host::Ip(ip) => SocketAddr::new(*ip, addr.port),
+                    Host::Domain(domain) => {
+                        match tokio::net::lookup_host((domain.as_str(), addr.port)).await {
+                            Ok(mut addrs) => addrs.next().ok_or(REPLY_HOST_UNREACHABLE)?,
+                            Err(_) => return Err(REPLY_HOST_UNREACHABLE),
+                        }
+                    }
+                };
+
+                TcpStream::connect(target)
+                    .await
+                    .map_err(|e| reply_for_io_error(&e))
+            }
+            Upstream::Socks5 {
+                addr: upstream_addr,
+                auth,
+            } => self.connect_via_upstream(upstream_addr, &auth, addr).await,
+        }
+    }
+
+    /// Splices `self.stream` and `dest_stream` together until either side
+    /// closes the connection.
+    async fn relay(&mut self, dest_stream: TcpStream) {
         loop {
             tokio::select! {
                 _ = self.stream.readable() => {
@@ -283,49 +477,458 @@ impl SOCKS5ClientConnection {
                 }
             }
         }
+    }
 
-        Ok(())
+    async fn send_socks4_reply(&mut self, status: u8) {
+        let data = [0x00, status, 0, 0, 0, 0, 0, 0];
+        let _ = write_all(&self.stream, &data).await;
     }
-}
 
-async fn read_exact(stream: &TcpStream, buf: &mut [u8]) -> std::io::Result<()> {
-    let mut offset = 0;
-    while offset < buf.len() {
-        stream.readable().await?;
+    /// Dials `upstream_addr` and performs the client side of the SOCKS5
+    /// handshake on behalf of the original client, requesting a CONNECT
+    /// to `target`. The domain name is forwarded unresolved when present,
+    /// so the upstream proxy performs its own DNS resolution. Returns the
+    /// established stream, or the SOCKS5 reply code to report back to the
+    /// original client on failure.
+    async fn connect_via_upstream(
+        &self,
+        upstream_addr: SocketAddr,
+        auth: &Option<(Vec<u8>, Vec<u8>)>,
+        target: &Address,
+    ) -> Result<TcpStream, u8> {
+        let stream = TcpStream::connect(upstream_addr)
+            .await
+            .map_err(|_| REPLY_GENERAL_FAILURE)?;
+
+        let methods: Vec<u8> = if auth.is_some() {
+            vec![NO_AUTHENTICATION_REQUIRED, USERNAME_PASSWORD_AUTHENTICATION]
+        } else {
+            vec![NO_AUTHENTICATION_REQUIRED]
+        };
+
+        let mut hello = vec![PROTOCOL_VERSION, methods.len() as u8];
+        hello.extend_from_slice(&methods);
+        write_all(&stream, &hello)
+            .await
+            .map_err(|_| REPLY_GENERAL_FAILURE)?;
+
+        let mut method_reply = [0u8; 2];
+        read_exact(&stream, &mut method_reply)
+            .await
+            .map_err(|_| REPLY_GENERAL_FAILURE)?;
 
-        match stream.try_read(&mut buf[offset..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                offset += n;
+        if method_reply[0] != PROTOCOL_VERSION {
+            return Err(REPLY_GENERAL_FAILURE);
+        }
+
+        match method_reply[1] {
+            NO_AUTHENTICATION_REQUIRED => {}
+            USERNAME_PASSWORD_AUTHENTICATION => {
+                let (user, pass) = auth.as_ref().ok_or(REPLY_GENERAL_FAILURE)?;
+
+                if user.len() > 255 || pass.len() > 255 {
+                    return Err(REPLY_GENERAL_FAILURE);
+                }
+
+                let mut auth_request = vec![AUTH_VERSION, user.len() as u8];
+                auth_request.extend_from_slice(user);
+                auth_request.push(pass.len() as u8);
+                auth_request.extend_from_slice(pass);
+                write_all(&stream, &auth_request)
+                    .await
+                    .map_err(|_| REPLY_GENERAL_FAILURE)?;
+
+                let mut auth_reply = [0u8; 2];
+                read_exact(&stream, &mut auth_reply)
+                    .await
+                    .map_err(|_| REPLY_GENERAL_FAILURE)?;
+                if auth_reply[1] != AUTH_SUCCESS {
+                    return Err(REPLY_CONNECTION_NOT_ALLOWED);
+                }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
+            _ => return Err(REPLY_GENERAL_FAILURE),
+        }
+
+        let mut request = vec![PROTOCOL_VERSION, PROXY_CMD_CONNECT, RESERVED];
+        match &target.host {
+            Host::Ip(IpAddr::V4(ipv4)) => {
+                request.push(ADDRESS_TYPE_IPV4);
+                request.extend_from_slice(&ipv4.octets());
             }
-            Err(e) => {
-                return Err(e.into());
+            Host::Ip(IpAddr::V6(ipv6)) => {
+                request.push(ADDRESS_TYPE_IPV6);
+                request.extend_from_slice(&ipv6.octets());
+            }
+            Host::Domain(domain) => {
+                request.push(ADDRESS_TYPE_DOMAIN_NAME);
+                request.push(domain.len() as u8);
+                request.extend_from_slice(domain.as_bytes());
+            }
+        }
+        request.extend_from_slice(&target.port.to_be_bytes());
+        write_all(&stream, &request)
+            .await
+            .map_err(|_| REPLY_GENERAL_FAILURE)?;
+
+        let (reply, _bound_addr) = read_socks5_reply(&stream)
+            .await
+            .map_err(|_| REPLY_GENERAL_FAILURE)?;
+        if reply != REPLY_SUCCEEDED {
+            return Err(reply);
+        }
+
+        Ok(stream)
+    }
+
+    async fn send_failure_reply(&mut self, reply: u8) {
+        let data = vec![
+            PROTOCOL_VERSION,
+            reply,
+            RESERVED,
+            ADDRESS_TYPE_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let _ = write_all(&self.stream, &data).await;
+    }
+
+    async fn process_udp_associate(&mut self) -> Result<(), SOCKS5ConnectionErr> {
+        let udp_socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(_) => {
+                self.send_failure_reply(REPLY_GENERAL_FAILURE).await;
+                return Err(SOCKS5ConnectionErr::ConnectionFailed);
+            }
+        };
+
+        // The UDP socket is bound to the wildcard address, which isn't a
+        // destination the client can actually send datagrams to. Report the
+        // address the client already knows us by (our end of the TCP
+        // control connection) alongside the UDP socket's bound port.
+        let bnd_port = udp_socket.local_addr().unwrap().port();
+        let bnd_ip = self
+            .stream
+            .local_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        let mut data = vec![PROTOCOL_VERSION, REPLY_SUCCEEDED, RESERVED];
+        match bnd_ip {
+            IpAddr::V4(ipv4) => {
+                data.push(ADDRESS_TYPE_IPV4);
+                data.extend_from_slice(&ipv4.octets());
+            }
+            IpAddr::V6(ipv6) => {
+                data.push(ADDRESS_TYPE_IPV6);
+                data.extend_from_slice(&ipv6.octets());
+            }
+        }
+        data.extend_from_slice(&bnd_port.to_be_bytes());
+        write_all(&self.stream, &data).await.unwrap();
+
+        // Known from the TCP control connection, so datagrams can be
+        // attributed to the legitimate client instead of trusting whichever
+        // source address happens to send the first packet.
+        let client_ip = match self.stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => {
+                self.send_failure_reply(REPLY_GENERAL_FAILURE).await;
+                return Err(SOCKS5ConnectionErr::ConnectionFailed);
+            }
+        };
+
+        let mut client_addr: Option<SocketAddr> = None;
+        let mut buf = [0u8; BUFFER_SIZE];
+
+        loop {
+            tokio::select! {
+                _ = self.stream.readable() => {
+                    let mut tmp = [0u8; BUFFER_SIZE];
+                    match self.stream.try_read(&mut tmp) {
+                        Ok(0) => break,
+                        Ok(_) => continue,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            continue;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                result = udp_socket.recv_from(&mut buf) => {
+                    let (n, src) = match result {
+                        Ok(v) => v,
+                        Err(_) => continue,
+                    };
+
+                    if client_addr.is_none() && src.ip() != client_ip {
+                        // Not yet bound to a client, and this datagram
+                        // doesn't come from the control connection's peer —
+                        // ignore it rather than letting a racing third party
+                        // claim the association.
+                        continue;
+                    }
+
+                    if client_addr.is_none() || client_addr == Some(src) {
+                        client_addr = Some(src);
+
+                        let (target, payload) = match parse_udp_request(&buf[..n]).await {
+                            Some(v) => v,
+                            None => continue,
+                        };
+
+                        let _ = udp_socket.send_to(payload, target).await;
+                    } else if let Some(client) = client_addr {
+                        let mut reply = vec![RESERVED, RESERVED, UDP_FRAGMENT_STANDALONE];
+                        match src.ip() {
+                            IpAddr::V4(ipv4) => {
+                                reply.push(ADDRESS_TYPE_IPV4);
+                                reply.extend_from_slice(&ipv4.octets());
+                            }
+                            IpAddr::V6(ipv6) => {
+                                reply.push(ADDRESS_TYPE_IPV6);
+                                reply.extend_from_slice(&ipv6.octets());
+                            }
+                        }
+                        reply.extend_from_slice(&src.port().to_be_bytes());
+                        reply.extend_from_slice(&buf[..n]);
+
+                        let _ = udp_socket.send_to(&reply, client).await;
+                    }
+                }
             }
         }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum FieldReadErr {
+    Io,
+    TooLong,
+}
+
+/// Reads a NUL-terminated field (SOCKS4's USERID or SOCKS4a's hostname) off
+/// `stream`, returning the bytes before the NUL. Rejects fields longer than
+/// `max_len` so a client can't make us buffer an unterminated stream
+/// forever.
+async fn read_nul_terminated_field(
+    stream: &TcpStream,
+    max_len: usize,
+) -> Result<Vec<u8>, FieldReadErr> {
+    let mut field = Vec::new();
+    loop {
+        let mut b = [0u8; 1];
+        read_exact(stream, &mut b).await.map_err(|_| FieldReadErr::Io)?;
+        if b[0] == 0 {
+            return Ok(field);
+        }
+        field.push(b[0]);
+        if field.len() > max_len {
+            return Err(FieldReadErr::TooLong);
+        }
+    }
+}
+
+fn command_err_reply(err: &SOCKS5ConnectionErr) -> Option<u8> {
+    match err {
+        SOCKS5ConnectionErr::UnsupportedCommand => Some(REPLY_COMMAND_NOT_SUPPORTED),
+        SOCKS5ConnectionErr::InvalidAddressType => Some(REPLY_ADDRESS_TYPE_NOT_SUPPORTED),
+        _ => None,
+    }
+}
+
+fn reply_for_io_error(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable => REPLY_HOST_UNREACHABLE,
+        io::ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        _ => REPLY_GENERAL_FAILURE,
     }
-    Ok(())
 }
 
-async fn write_all(stream: &TcpStream, buf: &[u8]) -> std::io::Result<()> {
-    let mut offset = 0;
-    while offset < buf.len() {
-        stream.writable().await?;
+async fn parse_udp_request(packet: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if packet.len() < 4 {
+        return None;
+    }
+
+    let frag = packet[2];
+    if frag != UDP_FRAGMENT_STANDALONE {
+        return None;
+    }
 
-        match stream.try_write(&buf[offset..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                offset += n;
+    let atyp = packet[3];
+    let mut offset = 4;
+
+    let ip = match atyp {
+        ADDRESS_TYPE_IPV4 => {
+            if packet.len() < offset + 4 {
+                return None;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
+            let ip = IpAddr::V4(Ipv4Addr::new(
+                packet[offset],
+                packet[offset + 1],
+                packet[offset + 2],
+                packet[offset + 3],
+            ));
+            offset += 4;
+            ip
+        }
+        ADDRESS_TYPE_IPV6 => {
+            if packet.len() < offset + 16 {
+                return None;
             }
-            Err(e) => {
-                return Err(e.into());
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&packet[offset..offset + 16]);
+            offset += 16;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        ADDRESS_TYPE_DOMAIN_NAME => {
+            if packet.len() < offset + 1 {
+                return None;
+            }
+            let len = packet[offset] as usize;
+            offset += 1;
+            if packet.len() < offset + len {
+                return None;
             }
+            let domain = String::from_utf8_lossy(&packet[offset..offset + len]).into_owned();
+            offset += len;
+            let mut addrs = tokio::net::lookup_host((domain.as_str(), 0)).await.ok()?;
+            addrs.next()?.ip()
         }
+        _ => return None,
+    };
+
+    if packet.len() < offset + 2 {
+        return None;
+    }
+    let port = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    offset += 2;
+
+    Some((SocketAddr::new(ip, port), &packet[offset..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_udp_request_rejects_fragmented_packets() {
+        let packet = [0x00, 0x00, 0x01, ADDRESS_TYPE_IPV4, 127, 0, 0, 1, 0, 80];
+        assert!(parse_udp_request(&packet).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn parse_udp_request_rejects_truncated_header() {
+        let packet = [0x00, 0x00, UDP_FRAGMENT_STANDALONE];
+        assert!(parse_udp_request(&packet).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn parse_udp_request_rejects_truncated_ipv4_address() {
+        let packet = [0x00, 0x00, UDP_FRAGMENT_STANDALONE, ADDRESS_TYPE_IPV4, 127, 0];
+        assert!(parse_udp_request(&packet).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn parse_udp_request_parses_ipv4() {
+        let mut packet = vec![0x00, 0x00, UDP_FRAGMENT_STANDALONE, ADDRESS_TYPE_IPV4];
+        packet.extend_from_slice(&[127, 0, 0, 1]);
+        packet.extend_from_slice(&80u16.to_be_bytes());
+        packet.extend_from_slice(b"payload");
+
+        let (target, payload) = parse_udp_request(&packet).await.unwrap();
+        assert_eq!(target, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 80));
+        assert_eq!(payload, b"payload");
+    }
+
+    #[tokio::test]
+    async fn parse_udp_request_parses_ipv6() {
+        let mut packet = vec![0x00, 0x00, UDP_FRAGMENT_STANDALONE, ADDRESS_TYPE_IPV6];
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(b"dns");
+
+        let (target, payload) = parse_udp_request(&packet).await.unwrap();
+        assert_eq!(target, SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 53));
+        assert_eq!(payload, b"dns");
+    }
+
+    #[tokio::test]
+    async fn parse_udp_request_rejects_unknown_address_type() {
+        let packet = [0x00, 0x00, UDP_FRAGMENT_STANDALONE, 0xff, 0, 80];
+        assert!(parse_udp_request(&packet).await.is_none());
+    }
+
+    #[test]
+    fn reply_for_io_error_maps_known_error_kinds() {
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::ConnectionRefused)),
+            REPLY_CONNECTION_REFUSED
+        );
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::NetworkUnreachable)),
+            REPLY_NETWORK_UNREACHABLE
+        );
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::HostUnreachable)),
+            REPLY_HOST_UNREACHABLE
+        );
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::TimedOut)),
+            REPLY_TTL_EXPIRED
+        );
+    }
+
+    #[test]
+    fn reply_for_io_error_defaults_to_general_failure() {
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::Other)),
+            REPLY_GENERAL_FAILURE
+        );
+        assert_eq!(
+            reply_for_io_error(&io::Error::from(io::ErrorKind::PermissionDenied)),
+            REPLY_GENERAL_FAILURE
+        );
+    }
+
+    /// Connects a loopback `TcpStream` pair for exercising stream-based
+    /// helpers without a real client.
+    async fn loopback_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = TcpStream::connect(addr);
+        let accept = listener.accept();
+        let (client, accepted) = tokio::join!(connect, accept);
+        let (server, _) = accepted.unwrap();
+        (client.unwrap(), server)
+    }
+
+    #[tokio::test]
+    async fn read_nul_terminated_field_returns_bytes_before_nul() {
+        let (client, server) = loopback_pair().await;
+        write_all(&client, b"alice\0").await.unwrap();
+
+        let field = read_nul_terminated_field(&server, SOCKS4_MAX_FIELD_LEN)
+            .await
+            .unwrap();
+        assert_eq!(field, b"alice");
+    }
+
+    #[tokio::test]
+    async fn read_nul_terminated_field_rejects_unterminated_overlong_input() {
+        let (client, server) = loopback_pair().await;
+        let oversized = vec![b'a'; SOCKS4_MAX_FIELD_LEN + 1];
+        write_all(&client, &oversized).await.unwrap();
+
+        let err = read_nul_terminated_field(&server, SOCKS4_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FieldReadErr::TooLong));
     }
-    Ok(())
 }